@@ -13,7 +13,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::env;
 use std::fmt;
 use std::fs::{self, File};
 use std::io::{self, Read};
@@ -36,8 +37,10 @@ use hyper::client::{Client, HttpConnector, Request};
 use hyper_tls::HttpsConnector;
 use jwt;
 use openssl;
+use rand;
+use serde::{Deserialize, Deserializer};
 use serde_json;
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Timeout};
 use url::form_urlencoded;
 use url::percent_encoding::{percent_encode, PATH_SEGMENT_ENCODE_SET, QUERY_ENCODE_SET};
 
@@ -45,11 +48,196 @@ use errors::*;
 
 type HyperClient = Client<HttpsConnector<HttpConnector>>;
 
+header! { (MetadataFlavor, "Metadata-Flavor") => [String] }
+
+/// URL of the GCE/GKE instance metadata server, reachable from any machine
+/// running on Google Cloud without any credentials being provisioned.
+const GCE_METADATA_TOKEN_URL: &str = "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+/// Configuration for the exponential backoff used to retry transient GCS
+/// failures (HTTP 408/429/5xx and transport errors).
+#[derive(Clone, Copy, Debug)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub base_delay: time::Duration,
+    pub max_elapsed: time::Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 8,
+            base_delay: time::Duration::from_millis(500),
+            max_elapsed: time::Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Tighter defaults for the cache-read path. A `get` that can't reach
+    /// GCS should fall back to local compilation quickly rather than
+    /// stalling the build for the full write-path retry budget.
+    pub fn default_for_get() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 3,
+            base_delay: time::Duration::from_millis(200),
+            max_elapsed: time::Duration::from_secs(2),
+        }
+    }
+}
+
+fn duration_millis(d: time::Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_nanos() / 1_000_000)
+}
+
+fn retry_delay(config: &RetryConfig, attempt_num: u32) -> time::Duration {
+    let exp = 1u64 << attempt_num.min(16);
+    let backoff_ms = duration_millis(config.base_delay).saturating_mul(exp).min(duration_millis(config.max_elapsed));
+    let jittered_ms = backoff_ms / 2 + rand::random::<u64>() % (backoff_ms / 2 + 1);
+    time::Duration::from_millis(jittered_ms)
+}
+
+fn is_retryable_status(status: &hyper::StatusCode) -> bool {
+    match status.as_u16() {
+        408 | 429 => true,
+        s => s >= 500,
+    }
+}
+
+/// Whether `err` represents a transient failure worth retrying: a retryable
+/// HTTP status, or a transport-level (hyper) error from sending the request.
+/// Errors while reading a response body are wrapped with context and are
+/// not retried.
+fn is_retryable(err: &Error) -> bool {
+    match *err.kind() {
+        ErrorKind::BadHTTPStatus(ref status) => is_retryable_status(status),
+        ErrorKind::Hyper(_) => true,
+        _ => false,
+    }
+}
+
+/// Run `attempt` and, if it fails with a retryable error, retry it with
+/// exponential backoff and jitter until `config.max_attempts` is reached or
+/// the backoff delays slept so far add up to `config.max_elapsed`, whichever
+/// comes first. Time spent in the attempts themselves is not counted.
+fn retry_with_backoff<F, T>(handle: Handle, config: RetryConfig, attempt: F) -> SFuture<T>
+    where F: Fn() -> SFuture<T> + 'static,
+          T: 'static,
+{
+    retry_with_backoff_from(handle, config, Rc::new(attempt), 0, time::Duration::from_secs(0))
+}
+
+fn retry_with_backoff_from<F, T>(handle: Handle, config: RetryConfig, attempt: Rc<F>, attempt_num: u32, elapsed: time::Duration) -> SFuture<T>
+    where F: Fn() -> SFuture<T> + 'static,
+          T: 'static,
+{
+    Box::new(attempt().or_else(move |e| {
+        if attempt_num + 1 >= config.max_attempts || elapsed >= config.max_elapsed || !is_retryable(&e) {
+            return future::err(e).boxed();
+        }
+
+        let delay = retry_delay(&config, attempt_num);
+        let timeout = match Timeout::new(delay, &handle) {
+            Ok(timeout) => timeout,
+            Err(e) => return future::err(e.into()).boxed(),
+        };
+
+        Box::new(timeout.map_err(Into::into).and_then(move |_| {
+            retry_with_backoff_from(handle, config, attempt, attempt_num + 1, elapsed + delay)
+        })) as SFuture<T>
+    }))
+}
+
+/// A GCS [storage class](https://cloud.google.com/storage/docs/storage-classes) for uploaded objects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageClass {
+    Standard,
+    Nearline,
+    Coldline,
+}
+
+impl fmt::Display for StorageClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            StorageClass::Standard => "STANDARD",
+            StorageClass::Nearline => "NEARLINE",
+            StorageClass::Coldline => "COLDLINE",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// A GCS [predefined ACL](https://cloud.google.com/storage/docs/access-control/lists#predefined-acl) applied to uploaded objects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PredefinedAcl {
+    Private,
+    ProjectPrivate,
+    PublicRead,
+    AuthenticatedRead,
+    BucketOwnerFullControl,
+    BucketOwnerRead,
+}
+
+impl fmt::Display for PredefinedAcl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            PredefinedAcl::Private => "private",
+            PredefinedAcl::ProjectPrivate => "projectPrivate",
+            PredefinedAcl::PublicRead => "publicRead",
+            PredefinedAcl::AuthenticatedRead => "authenticatedRead",
+            PredefinedAcl::BucketOwnerFullControl => "bucketOwnerFullControl",
+            PredefinedAcl::BucketOwnerRead => "bucketOwnerRead",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Serialize)]
+struct ObjectMetadata<'a> {
+    name: &'a str,
+    #[serde(rename = "storageClass", skip_serializing_if = "Option::is_none")]
+    storage_class: Option<String>,
+}
+
+/// Metadata for a single object, as returned by the GCS object-listing API.
+#[derive(Clone, Debug, Deserialize)]
+struct GCSObjectMetadata {
+    name: String,
+    #[serde(deserialize_with = "deserialize_size")]
+    size: u64,
+    #[serde(rename = "timeCreated")]
+    time_created: chrono::DateTime<chrono::UTC>,
+}
+
+/// The GCS API reports object `size` as a decimal string, not a JSON number.
+fn deserialize_size<'de, D>(deserializer: D) -> ::std::result::Result<u64, D::Error>
+    where D: Deserializer<'de>
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(::serde::de::Error::custom)
+}
+
+#[derive(Deserialize)]
+struct ListObjectsResponse {
+    #[serde(rename = "nextPageToken")]
+    next_page_token: Option<String>,
+    items: Option<Vec<GCSObjectMetadata>>,
+}
+
 /// A GCS bucket
 struct Bucket {
     name: String,
     base_url: String,
     client: HyperClient,
+    storage_class: Option<StorageClass>,
+    predefined_acl: Option<PredefinedAcl>,
+    /// Retry budget for writes, listing, and deletion.
+    retry_config: RetryConfig,
+    /// Retry budget for reads, kept much tighter than `retry_config` so a
+    /// `get` fails fast into a local-compilation fallback instead of
+    /// stalling the build during a GCS outage.
+    get_retry_config: RetryConfig,
+    handle: Handle,
 }
 
 impl fmt::Display for Bucket {
@@ -59,90 +247,339 @@ impl fmt::Display for Bucket {
 }
 
 impl Bucket {
-    pub fn new(name: String, base_url: String, handle: &Handle) -> Result<Bucket> {
+    pub fn new(name: String,
+               base_url: String,
+               storage_class: Option<StorageClass>,
+               predefined_acl: Option<PredefinedAcl>,
+               retry_config: RetryConfig,
+               get_retry_config: RetryConfig,
+               handle: &Handle) -> Result<Bucket> {
         let client = Client::configure()
                         .connector(HttpsConnector::new(1, handle)?)
                         .build(handle);
 
-        Ok(Bucket { name, base_url, client })
+        Ok(Bucket { name, base_url, client, storage_class, predefined_acl, retry_config, get_retry_config, handle: handle.clone() })
     }
 
-    fn get(&self, key: &str, cred_provider: &GCSCredentialProvider) -> SFuture<Vec<u8>> {
+    fn get(&self, key: &str, cred_provider: &Rc<GCSCredentialProvider>) -> SFuture<Vec<u8>> {
         let url = format!("{}/download/storage/v1/b/{}/o/{}?alt=media",
                     self.base_url,
                     percent_encode(self.name.as_bytes(), PATH_SEGMENT_ENCODE_SET),
                     percent_encode(key.as_bytes(), PATH_SEGMENT_ENCODE_SET));
 
         let client = self.client.clone();
+        let cred_provider = cred_provider.clone();
 
-        Box::new(cred_provider.credentials(&self.client).and_then(move |creds| {
-            let mut request = Request::new(Method::Get, url.parse().unwrap());
-            request.headers_mut()
-                .set(Authorization(Bearer { token: creds.token }));
-            client.request(request).chain_err(move || {
-                format!("failed GET: {}", url)
-            }).and_then(|res| {
-                if res.status().is_success() {
-                    Ok(res.body())
-                } else {
-                    Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
-                }
-            }).and_then(|body| {
-                body.fold(Vec::new(), |mut body, chunk| {
-                    body.extend_from_slice(&chunk);
-                    Ok::<_, hyper::Error>(body)
-                }).chain_err(|| {
-                    "failed to read HTTP body"
-                })
-            })
-        }))
+        let attempt = move || -> SFuture<Vec<u8>> {
+            let url = url.clone();
+            let client = client.clone();
+
+            if cred_provider.is_anonymous() {
+                let request = Request::new(Method::Get, url.parse().unwrap());
+                Box::new(client.request(request).map_err(Error::from).and_then(|res| {
+                    if res.status().is_success() {
+                        Ok(res.body())
+                    } else {
+                        Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+                    }
+                }).and_then(|body| {
+                    body.fold(Vec::new(), |mut body, chunk| {
+                        body.extend_from_slice(&chunk);
+                        Ok::<_, hyper::Error>(body)
+                    }).chain_err(|| {
+                        "failed to read HTTP body"
+                    })
+                }))
+            } else {
+                Box::new(cred_provider.credentials(&client).and_then(move |creds| {
+                    let mut request = Request::new(Method::Get, url.parse().unwrap());
+                    request.headers_mut()
+                        .set(Authorization(Bearer { token: creds.token }));
+                    client.request(request).map_err(Error::from).and_then(|res| {
+                        if res.status().is_success() {
+                            Ok(res.body())
+                        } else {
+                            Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+                        }
+                    }).and_then(|body| {
+                        body.fold(Vec::new(), |mut body, chunk| {
+                            body.extend_from_slice(&chunk);
+                            Ok::<_, hyper::Error>(body)
+                        }).chain_err(|| {
+                            "failed to read HTTP body"
+                        })
+                    })
+                }))
+            }
+        };
+
+        retry_with_backoff(self.handle.clone(), self.get_retry_config, attempt)
+    }
+
+    fn put(&self, key: &str, content: Vec<u8>, cred_provider: &Rc<GCSCredentialProvider>) -> SFuture<()> {
+        if cred_provider.is_anonymous() {
+            return future::err("cannot write to a GCS cache configured in anonymous read-only mode".into()).boxed();
+        }
+
+        if self.storage_class.is_some() || self.predefined_acl.is_some() {
+            self.put_multipart(key, content, cred_provider)
+        } else {
+            self.put_simple(key, content, cred_provider)
+        }
     }
 
-    fn put(&self, key: &str, content: Vec<u8>, cred_provider: &GCSCredentialProvider) -> SFuture<()> {
+    fn put_simple(&self, key: &str, content: Vec<u8>, cred_provider: &Rc<GCSCredentialProvider>) -> SFuture<()> {
         let url = format!("{}/upload/storage/v1/b/{}/o?name={}&uploadType=media",
                     self.base_url,
                     percent_encode(self.name.as_bytes(), PATH_SEGMENT_ENCODE_SET),
                     percent_encode(key.as_bytes(), QUERY_ENCODE_SET));
 
         let client = self.client.clone();
+        let cred_provider = cred_provider.clone();
+        let content = Rc::new(content);
 
-        Box::new(cred_provider.credentials(&client).and_then(move |creds| {
-            let mut request = Request::new(Method::Post, url.parse().unwrap());
-            {
-                let mut headers = request.headers_mut();
-                headers.set(Authorization(Bearer { token: creds.token }));
-                headers.set(ContentType("application/octet-stream".parse().unwrap()));
-                headers.set(ContentLength(content.len() as u64));
+        let attempt = move || -> SFuture<()> {
+            let url = url.clone();
+            let client = client.clone();
+            let content = (*content).clone();
+            Box::new(cred_provider.credentials(&client).and_then(move |creds| {
+                let mut request = Request::new(Method::Post, url.parse().unwrap());
+                {
+                    let mut headers = request.headers_mut();
+                    headers.set(Authorization(Bearer { token: creds.token }));
+                    headers.set(ContentType("application/octet-stream".parse().unwrap()));
+                    headers.set(ContentLength(content.len() as u64));
+                }
+                request.set_body(content);
+
+                client.request(request).then(Self::handle_put_response)
+            }))
+        };
+
+        retry_with_backoff(self.handle.clone(), self.retry_config, attempt)
+    }
+
+    /// Upload `content`, attaching object metadata (storage class and/or
+    /// predefined ACL) via a `multipart/related` insert, as plain `media`
+    /// uploads can't carry object metadata.
+    fn put_multipart(&self, key: &str, content: Vec<u8>, cred_provider: &Rc<GCSCredentialProvider>) -> SFuture<()> {
+        let mut url = format!("{}/upload/storage/v1/b/{}/o?name={}&uploadType=multipart",
+                    self.base_url,
+                    percent_encode(self.name.as_bytes(), PATH_SEGMENT_ENCODE_SET),
+                    percent_encode(key.as_bytes(), QUERY_ENCODE_SET));
+        if let Some(predefined_acl) = self.predefined_acl {
+            url.push_str(&format!("&predefinedAcl={}", predefined_acl));
+        }
+
+        let metadata = ObjectMetadata {
+            name: key,
+            storage_class: self.storage_class.map(|c| c.to_string()),
+        };
+        let metadata_json = match serde_json::to_string(&metadata) {
+            Ok(json) => json,
+            Err(e) => return future::err(e.into()).boxed(),
+        };
+
+        let boundary = choose_multipart_boundary(&content);
+        let mut body = Vec::with_capacity(metadata_json.len() + content.len() + 256);
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        body.extend_from_slice(b"Content-Type: application/json; charset=UTF-8\r\n\r\n");
+        body.extend_from_slice(metadata_json.as_bytes());
+        body.extend_from_slice(format!("\r\n--{}\r\n", boundary).as_bytes());
+        body.extend_from_slice(b"Content-Type: application/octet-stream\r\n\r\n");
+        body.extend_from_slice(&content);
+        body.extend_from_slice(format!("\r\n--{}--", boundary).as_bytes());
+
+        let client = self.client.clone();
+        let cred_provider = cred_provider.clone();
+        let body = Rc::new(body);
+        let content_type = format!("multipart/related; boundary={}", boundary);
+
+        let attempt = move || -> SFuture<()> {
+            let url = url.clone();
+            let client = client.clone();
+            let body = (*body).clone();
+            let content_type = content_type.clone();
+            Box::new(cred_provider.credentials(&client).and_then(move |creds| {
+                let mut request = Request::new(Method::Post, url.parse().unwrap());
+                {
+                    let mut headers = request.headers_mut();
+                    headers.set(Authorization(Bearer { token: creds.token }));
+                    headers.set(ContentType(content_type.parse().unwrap()));
+                    headers.set(ContentLength(body.len() as u64));
+                }
+                request.set_body(body);
+
+                client.request(request).then(Self::handle_put_response)
+            }))
+        };
+
+        retry_with_backoff(self.handle.clone(), self.retry_config, attempt)
+    }
+
+    fn handle_put_response(result: ::std::result::Result<hyper::Response, hyper::Error>) -> Result<()> {
+        match result {
+            Ok(res) => {
+                if res.status().is_success() {
+                    trace!("PUT succeeded");
+                    Ok(())
+                } else {
+                    trace!("PUT failed with HTTP status: {}", res.status());
+                    Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+                }
             }
-            request.set_body(content);
+            Err(e) => {
+                trace!("PUT failed with error: {:?}", e);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// List every object in the bucket via the JSON listing API, following
+    /// `nextPageToken` pagination until it's absent.
+    fn list_objects(&self, cred_provider: &Rc<GCSCredentialProvider>) -> SFuture<Vec<GCSObjectMetadata>> {
+        Self::list_objects_page(self.base_url.clone(),
+                                 self.name.clone(),
+                                 self.client.clone(),
+                                 self.handle.clone(),
+                                 self.retry_config,
+                                 cred_provider.clone(),
+                                 None,
+                                 Vec::new())
+    }
+
+    fn list_objects_page(base_url: String,
+                          name: String,
+                          client: HyperClient,
+                          handle: Handle,
+                          retry_config: RetryConfig,
+                          cred_provider: Rc<GCSCredentialProvider>,
+                          page_token: Option<String>,
+                          mut items: Vec<GCSObjectMetadata>) -> SFuture<Vec<GCSObjectMetadata>> {
+        let mut url = format!("{}/storage/v1/b/{}/o?maxResults=1000",
+                    base_url,
+                    percent_encode(name.as_bytes(), PATH_SEGMENT_ENCODE_SET));
+        if let Some(ref token) = page_token {
+            url.push_str(&format!("&pageToken={}", percent_encode(token.as_bytes(), QUERY_ENCODE_SET)));
+        }
 
-            client.request(request).then(|result| {
-                match result {
-                    Ok(res) => {
+        let attempt = {
+            let url = url.clone();
+            let client = client.clone();
+            let cred_provider = cred_provider.clone();
+            move || -> SFuture<ListObjectsResponse> {
+                let url = url.clone();
+                let client = client.clone();
+                Box::new(cred_provider.credentials(&client).and_then(move |creds| {
+                    let mut request = Request::new(Method::Get, url.parse().unwrap());
+                    request.headers_mut()
+                        .set(Authorization(Bearer { token: creds.token }));
+                    client.request(request).map_err(Error::from).and_then(|res| {
                         if res.status().is_success() {
-                            trace!("PUT succeeded");
-                            Ok(())
+                            Ok(res.body())
                         } else {
-                            trace!("PUT failed with HTTP status: {}", res.status());
                             Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
                         }
-                    }
-                    Err(e) => {
-                        trace!("PUT failed with error: {:?}", e);
-                        Err(e.into())
-                    }
+                    }).and_then(|body| {
+                        body.fold(Vec::new(), |mut body, chunk| {
+                            body.extend_from_slice(&chunk);
+                            Ok::<_, hyper::Error>(body)
+                        }).chain_err(|| {
+                            "failed to read HTTP body"
+                        })
+                    }).and_then(|body| {
+                        let body_str = String::from_utf8(body)?;
+                        Ok(serde_json::from_str(&body_str)?)
+                    })
+                }))
+            }
+        };
+
+        let page = retry_with_backoff(handle.clone(), retry_config, attempt);
+
+        Box::new(page.and_then(move |page| {
+            items.extend(page.items.unwrap_or_default());
+            match page.next_page_token {
+                Some(token) => {
+                    Self::list_objects_page(base_url, name, client, handle, retry_config, cred_provider, Some(token), items)
                 }
-            })
+                None => Box::new(future::ok(items)) as SFuture<Vec<GCSObjectMetadata>>,
+            }
         }))
     }
+
+    /// Delete a single object. A 404 (already gone) is treated as success so
+    /// an eviction pass racing with another deletion doesn't fail.
+    fn delete_object(&self, key: &str, cred_provider: &Rc<GCSCredentialProvider>) -> SFuture<()> {
+        let url = format!("{}/storage/v1/b/{}/o/{}",
+                    self.base_url,
+                    percent_encode(self.name.as_bytes(), PATH_SEGMENT_ENCODE_SET),
+                    percent_encode(key.as_bytes(), PATH_SEGMENT_ENCODE_SET));
+
+        let client = self.client.clone();
+        let cred_provider = cred_provider.clone();
+
+        let attempt = move || -> SFuture<()> {
+            let url = url.clone();
+            let client = client.clone();
+            Box::new(cred_provider.credentials(&client).and_then(move |creds| {
+                let mut request = Request::new(Method::Delete, url.parse().unwrap());
+                request.headers_mut()
+                    .set(Authorization(Bearer { token: creds.token }));
+                client.request(request).then(|result| {
+                    match result {
+                        Ok(res) => {
+                            if res.status().is_success() || res.status() == hyper::StatusCode::NotFound {
+                                Ok(())
+                            } else {
+                                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+                            }
+                        }
+                        Err(e) => Err(e.into()),
+                    }
+                })
+            }))
+        };
+
+        retry_with_backoff(self.handle.clone(), self.retry_config, attempt)
+    }
+}
+
+/// Pick a multipart boundary guaranteed not to appear in `content`. Cache
+/// payloads are arbitrary binary blobs, so a fixed boundary constant could
+/// in principle collide with one and corrupt the multipart parse on GCS's
+/// end; regenerate with a fresh random suffix on the rare chance it does.
+fn choose_multipart_boundary(content: &[u8]) -> String {
+    loop {
+        let candidate = format!("sccache_gcs_multipart_boundary_{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>());
+        if !content.windows(candidate.len()).any(|window| window == candidate.as_bytes()) {
+            return candidate;
+        }
+    }
 }
 
 pub struct GCSCredentialProvider {
     read_only: bool,
-    credentials_path: String,
+    source: GCSCredentialSource,
+    retry_config: RetryConfig,
+    handle: Handle,
     cached_credentials: RefCell<Option<Shared<SFuture<GCSCredential>>>>,
 }
 
+/// Where a `GCSCredentialProvider` should get its access tokens from.
+enum GCSCredentialSource {
+    /// A service-account JSON key file, signed into a JWT on each refresh.
+    ServiceAccountKey(String),
+    /// A gcloud user credential (`"type": "authorized_user"`), refreshed via
+    /// its OAuth2 refresh token.
+    UserRefreshToken(String),
+    /// The GCE/GKE instance metadata server, for workloads running on Google Cloud.
+    GceMetadataServer,
+    /// No credentials at all: requests are sent unauthenticated, for reading
+    /// from a public bucket. Uploads are not possible in this mode.
+    Anonymous,
+}
+
 #[derive(Debug, Deserialize)]
 struct ServiceAccountKey {
     #[serde(rename = "type")]
@@ -157,6 +594,15 @@ struct ServiceAccountKey {
     auth_provider_x509_cert_url: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct AuthorizedUserKey {
+    #[serde(rename = "type")]
+    _type: String,
+    client_id: String,
+    client_secret: String,
+    refresh_token: String,
+}
+
 #[derive(Serialize)]
 struct JwtClaims {
     #[serde(rename = "iss")]
@@ -184,27 +630,91 @@ pub struct GCSCredential {
 }
 
 impl GCSCredentialProvider {
-    pub fn new(read_only: bool, credentials_path: String) -> Self {
+    /// Create a new `GCSCredentialProvider`.
+    ///
+    /// `credentials_path` is resolved following the standard Application
+    /// Default Credentials search order: an explicitly configured path, the
+    /// `GOOGLE_APPLICATION_CREDENTIALS` environment variable, then the
+    /// well-known `gcloud` config location. The first two are used as given,
+    /// whether or not the file exists; the `gcloud` location is used only if
+    /// the file exists. If none of them applies, credentials are instead
+    /// requested from the GCE/GKE instance metadata server.
+    pub fn new(read_only: bool, credentials_path: Option<String>, retry_config: RetryConfig, handle: Handle) -> Self {
+        let source = match Self::resolve_credentials_path(credentials_path) {
+            Some(path) => Self::credential_source_for_path(path),
+            None => GCSCredentialSource::GceMetadataServer,
+        };
         GCSCredentialProvider {
             read_only,
-            credentials_path,
+            source,
+            retry_config,
+            handle,
+            cached_credentials: RefCell::new(None),
+        }
+    }
+
+    /// Create a `GCSCredentialProvider` that sends every request
+    /// unauthenticated, for reading from a public bucket. `credentials()`
+    /// is never called and uploads are rejected by `Bucket::put`.
+    pub fn new_anonymous(retry_config: RetryConfig, handle: Handle) -> Self {
+        GCSCredentialProvider {
+            read_only: true,
+            source: GCSCredentialSource::Anonymous,
+            retry_config,
+            handle,
             cached_credentials: RefCell::new(None),
         }
     }
 
-    fn auth_request_jwt(&self, expire_at: &chrono::DateTime<chrono::UTC>) -> Result<String> {
-        let metadata = fs::metadata(&self.credentials_path).chain_err(|| {
+    fn is_anonymous(&self) -> bool {
+        match self.source {
+            GCSCredentialSource::Anonymous => true,
+            _ => false,
+        }
+    }
+
+    fn resolve_credentials_path(credentials_path: Option<String>) -> Option<String> {
+        credentials_path
+            .or_else(|| env::var("GOOGLE_APPLICATION_CREDENTIALS").ok())
+            .or_else(|| {
+                env::home_dir()
+                    .map(|home| home.join(".config/gcloud/application_default_credentials.json"))
+                    .filter(|path| path.is_file())
+                    .and_then(|path| path.to_str().map(str::to_owned))
+            })
+    }
+
+    /// Inspect the `"type"` field of a credentials file to decide whether it
+    /// holds a service-account key or a gcloud user (`authorized_user`)
+    /// credential.
+    fn credential_source_for_path(path: String) -> GCSCredentialSource {
+        match Self::read_credentials_type(&path) {
+            Ok(ref kind) if kind == "authorized_user" => GCSCredentialSource::UserRefreshToken(path),
+            _ => GCSCredentialSource::ServiceAccountKey(path),
+        }
+    }
+
+    fn read_credentials_type(path: &str) -> Result<String> {
+        let mut file = File::open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let value: serde_json::Value = serde_json::from_str(&contents)?;
+        Ok(value.get("type").and_then(|t| t.as_str()).unwrap_or("").to_owned())
+    }
+
+    fn auth_request_jwt(credentials_path: &str, expire_at: &chrono::DateTime<chrono::UTC>, read_only: bool) -> Result<String> {
+        let metadata = fs::metadata(credentials_path).chain_err(|| {
             "Couldn't stat GCS credentials file"
         })?;
         if !metadata.is_file() {
             bail!("Couldn't open GCS credentials file.");
         }
-        let mut file = File::open(&self.credentials_path)?;
+        let mut file = File::open(credentials_path)?;
         let mut service_account_json = String::new();
         file.read_to_string(&mut service_account_json)?;
         let sa_key: ServiceAccountKey = serde_json::from_str(&service_account_json)?;
 
-        let scope = (if self.read_only {
+        let scope = (if read_only {
             "https://www.googleapis.com/auth/devstorage.readonly"
         } else {
             "https://www.googleapis.com/auth/devstorage.read_write"
@@ -241,46 +751,33 @@ impl GCSCredentialProvider {
         };
 
         if needs_refresh {
+            let handle = self.handle.clone();
+            let retry_config = self.retry_config;
             let client = client.clone();
-            let expires_at = chrono::UTC::now() + chrono::Duration::minutes(59);
-            let auth_jwt = self.auth_request_jwt(&expires_at);
-            let credentials: SFuture<_> = Box::new(future::result(auth_jwt).and_then(move |auth_jwt| {
-                let url = "https://www.googleapis.com/oauth2/v4/token";
-                let params = form_urlencoded::Serializer::new(String::new())
-                    .append_pair("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer")
-                    .append_pair("assertion", &auth_jwt)
-                    .finish();
 
-                let mut request = Request::new(Method::Post, url.parse().unwrap());
-                {
-                    let mut headers = request.headers_mut();
-                    headers.set(ContentType("application/x-www-form-urlencoded".parse().unwrap()));
-                    headers.set(ContentLength(params.len() as u64));
+            let credentials: SFuture<GCSCredential> = match self.source {
+                GCSCredentialSource::ServiceAccountKey(ref path) => {
+                    let path = path.clone();
+                    let read_only = self.read_only;
+                    retry_with_backoff(handle, retry_config, move || {
+                        Self::request_credentials_from_service_account_key(&client, &path, read_only)
+                    })
                 }
-                request.set_body(params);
-
-                client.request(request).map_err(Into::into)
-            }).and_then(move |res| {
-                if res.status().is_success() {
-                    Ok(res.body())
-                } else {
-                    Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+                GCSCredentialSource::UserRefreshToken(ref path) => {
+                    let path = path.clone();
+                    retry_with_backoff(handle, retry_config, move || {
+                        Self::request_credentials_from_refresh_token(&client, &path)
+                    })
                 }
-            }).and_then(move |body| {
-                body.fold(Vec::new(), |mut body, chunk| {
-                    body.extend_from_slice(&chunk);
-                    Ok::<_, hyper::Error>(body)
-                }).chain_err(|| {
-                    "failed to read HTTP body"
-                })
-            }).and_then(move |body| {
-                let body_str = String::from_utf8(body)?;
-                let token_msg: TokenMsg = serde_json::from_str(&body_str)?;
-                Ok(GCSCredential {
-                    token: token_msg.access_token,
-                    expiration_time: expires_at,
-                })
-            }));
+                GCSCredentialSource::GceMetadataServer => {
+                    retry_with_backoff(handle, retry_config, move || {
+                        Self::request_credentials_from_metadata_server(&client)
+                    })
+                }
+                GCSCredentialSource::Anonymous => {
+                    future::err("no credentials available in anonymous mode".into()).boxed()
+                }
+            };
 
             *future_opt = Some(credentials.shared());
         };
@@ -292,32 +789,289 @@ impl GCSCredentialProvider {
             }
         }))
     }
+
+    fn request_credentials_from_service_account_key(client: &HyperClient, credentials_path: &str, read_only: bool) -> SFuture<GCSCredential> {
+        let client = client.clone();
+        let expires_at = chrono::UTC::now() + chrono::Duration::minutes(59);
+        let auth_jwt = Self::auth_request_jwt(credentials_path, &expires_at, read_only);
+        Box::new(future::result(auth_jwt).and_then(move |auth_jwt| {
+            let url = "https://www.googleapis.com/oauth2/v4/token";
+            let params = form_urlencoded::Serializer::new(String::new())
+                .append_pair("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer")
+                .append_pair("assertion", &auth_jwt)
+                .finish();
+
+            let mut request = Request::new(Method::Post, url.parse().unwrap());
+            {
+                let mut headers = request.headers_mut();
+                headers.set(ContentType("application/x-www-form-urlencoded".parse().unwrap()));
+                headers.set(ContentLength(params.len() as u64));
+            }
+            request.set_body(params);
+
+            client.request(request).map_err(Into::into)
+        }).and_then(move |res| {
+            if res.status().is_success() {
+                Ok(res.body())
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }).and_then(move |body| {
+            body.fold(Vec::new(), |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                Ok::<_, hyper::Error>(body)
+            }).chain_err(|| {
+                "failed to read HTTP body"
+            })
+        }).and_then(move |body| {
+            let body_str = String::from_utf8(body)?;
+            let token_msg: TokenMsg = serde_json::from_str(&body_str)?;
+            Ok(GCSCredential {
+                token: token_msg.access_token,
+                expiration_time: expires_at,
+            })
+        }))
+    }
+
+    /// Refresh an access token from a gcloud `authorized_user` credential by
+    /// exchanging its refresh token. The refresh token itself does not
+    /// change, so it's simply re-read from `credentials_path` on every call.
+    fn request_credentials_from_refresh_token(client: &HyperClient, credentials_path: &str) -> SFuture<GCSCredential> {
+        let client = client.clone();
+        let user_key = (|| -> Result<AuthorizedUserKey> {
+            let mut file = File::open(credentials_path)?;
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            Ok(serde_json::from_str(&contents)?)
+        })();
+
+        Box::new(future::result(user_key).and_then(move |user_key| {
+            let url = "https://www.googleapis.com/oauth2/v4/token";
+            let params = form_urlencoded::Serializer::new(String::new())
+                .append_pair("grant_type", "refresh_token")
+                .append_pair("refresh_token", &user_key.refresh_token)
+                .append_pair("client_id", &user_key.client_id)
+                .append_pair("client_secret", &user_key.client_secret)
+                .finish();
+
+            let mut request = Request::new(Method::Post, url.parse().unwrap());
+            {
+                let mut headers = request.headers_mut();
+                headers.set(ContentType("application/x-www-form-urlencoded".parse().unwrap()));
+                headers.set(ContentLength(params.len() as u64));
+            }
+            request.set_body(params);
+
+            client.request(request).map_err(Into::into)
+        }).and_then(|res| {
+            if res.status().is_success() {
+                Ok(res.body())
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }).and_then(|body| {
+            body.fold(Vec::new(), |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                Ok::<_, hyper::Error>(body)
+            }).chain_err(|| {
+                "failed to read HTTP body"
+            })
+        }).and_then(|body| {
+            let body_str = String::from_utf8(body)?;
+            let token_msg: TokenMsg = serde_json::from_str(&body_str)?;
+            let expiration_time = chrono::UTC::now() + chrono::Duration::seconds(token_msg.expires_in as i64);
+            Ok(GCSCredential {
+                token: token_msg.access_token,
+                expiration_time,
+            })
+        }))
+    }
+
+    /// Fetch a token for the VM's attached service account from the GCE/GKE
+    /// instance metadata server.
+    fn request_credentials_from_metadata_server(client: &HyperClient) -> SFuture<GCSCredential> {
+        let mut request = Request::new(Method::Get, GCE_METADATA_TOKEN_URL.parse().unwrap());
+        request.headers_mut().set(MetadataFlavor("Google".to_owned()));
+
+        Box::new(client.request(request).map_err(Error::from).and_then(|res| {
+            if res.status().is_success() {
+                Ok(res.body())
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }).and_then(|body| {
+            body.fold(Vec::new(), |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                Ok::<_, hyper::Error>(body)
+            }).chain_err(|| {
+                "failed to read HTTP body"
+            })
+        }).and_then(|body| {
+            let body_str = String::from_utf8(body)?;
+            let token_msg: TokenMsg = serde_json::from_str(&body_str)?;
+            let expiration_time = chrono::UTC::now() + chrono::Duration::seconds(token_msg.expires_in as i64);
+            Ok(GCSCredential {
+                token: token_msg.access_token,
+                expiration_time,
+            })
+        }))
+    }
 }
 
+/// Minimum time between background size-refresh/eviction passes. A pass
+/// walks the whole bucket listing, so running one per `get`/`put` would
+/// turn every cache operation into an O(objects) listing call (and retry
+/// storm if the bucket is large or GCS is degraded).
+const MAINTENANCE_MIN_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
 /// A cache that stores entries in Google Cloud Storage
 pub struct GCSCache {
     /// The GCS bucket
     bucket: Rc<Bucket>,
     /// Credential provider for GCS
-    credential_provider: GCSCredentialProvider,
+    credential_provider: Rc<GCSCredentialProvider>,
+    /// The maximum size of the cache, in bytes, if one is configured
+    max_size: Option<u64>,
+    /// The size of the cache as of the last background maintenance pass
+    current_size: Rc<Cell<Option<u64>>>,
+    /// Whether a background maintenance pass is currently running
+    maintenance_running: Rc<Cell<bool>>,
+    /// When the last background maintenance pass was started
+    last_maintenance: Rc<Cell<Option<time::Instant>>>,
+    handle: Handle,
 }
 
 impl GCSCache {
-    /// Create a new `GCSCache` storing data in `bucket`
+    /// Create a new `GCSCache` storing data in `bucket`.
+    ///
+    /// `get_retry_config` governs the read path and should generally be
+    /// much tighter than `retry_config` (writes, listing, deletion) so a
+    /// cache miss during a GCS outage fails fast into local compilation
+    /// rather than stalling the build; see `RetryConfig::default_for_get`.
     pub fn new(bucket: String,
                endpoint: String,
+               storage_class: Option<StorageClass>,
+               predefined_acl: Option<PredefinedAcl>,
+               retry_config: RetryConfig,
+               get_retry_config: RetryConfig,
+               max_size: Option<u64>,
                credential_provider: GCSCredentialProvider,
                handle: &Handle) -> Result<GCSCache>
     {
         Ok(GCSCache {
-            bucket: Rc::new(Bucket::new(bucket, endpoint, handle)?),
-            credential_provider: credential_provider,
+            bucket: Rc::new(Bucket::new(bucket, endpoint, storage_class, predefined_acl, retry_config, get_retry_config, handle)?),
+            credential_provider: Rc::new(credential_provider),
+            max_size,
+            current_size: Rc::new(Cell::new(None)),
+            maintenance_running: Rc::new(Cell::new(false)),
+            last_maintenance: Rc::new(Cell::new(None)),
+            handle: handle.clone(),
         })
     }
+
+    /// List the bucket, record the total size, and if it exceeds `max_size`,
+    /// delete the least-recently-created objects until back under the cap.
+    /// Runs detached on the event loop so it doesn't delay the cache
+    /// operation that triggered it. Throttled to at most once per
+    /// `MAINTENANCE_MIN_INTERVAL` and never run concurrently with itself,
+    /// since a full bucket listing is expensive to run on every `get`/`put`.
+    /// Failures are logged and otherwise ignored.
+    fn maybe_spawn_maintenance_pass(&self) {
+        // An anonymous reader can't authorize a listing (or an eviction), and
+        // without a configured cap there's nothing to evict, so the only
+        // reason to list would be `current_size` visibility — not worth a
+        // recurring whole-bucket scan for every GCS user.
+        if self.credential_provider.is_anonymous() || self.max_size.is_none() {
+            return;
+        }
+        if self.maintenance_running.get() {
+            return;
+        }
+        if let Some(last) = self.last_maintenance.get() {
+            if last.elapsed() < MAINTENANCE_MIN_INTERVAL {
+                return;
+            }
+        }
+        self.maintenance_running.set(true);
+        self.last_maintenance.set(Some(time::Instant::now()));
+
+        let max_size = self.max_size;
+        let bucket = self.bucket.clone();
+        let credential_provider = self.credential_provider.clone();
+        let current_size = self.current_size.clone();
+        let maintenance_running = self.maintenance_running.clone();
+
+        let task: SFuture<()> = Box::new(bucket.list_objects(&credential_provider).and_then(move |mut objects| {
+            let total: u64 = objects.iter().map(|o| o.size).sum();
+            current_size.set(Some(total));
+
+            let max_size = match max_size {
+                Some(max_size) if total > max_size => max_size,
+                _ => return Box::new(future::ok(())) as SFuture<()>,
+            };
+
+            objects.sort_by_key(|o| o.time_created);
+            let to_delete = select_objects_to_evict(total, max_size, &objects);
+
+            let deletions = to_delete.into_iter().map(|object| {
+                let bucket = bucket.clone();
+                let credential_provider = credential_provider.clone();
+                let size = object.size;
+                bucket.delete_object(&object.name, &credential_provider).then(move |result| {
+                    match result {
+                        Ok(()) => Ok(size),
+                        Err(e) => {
+                            warn!("failed to evict GCS cache object {}: {:?}", object.name, e);
+                            Ok::<_, Error>(0)
+                        }
+                    }
+                })
+            }).collect::<Vec<_>>();
+
+            let current_size = current_size.clone();
+            Box::new(future::join_all(deletions).map(move |freed| {
+                let freed: u64 = freed.into_iter().sum();
+                if let Some(total) = current_size.get() {
+                    current_size.set(Some(total.saturating_sub(freed)));
+                }
+            })) as SFuture<()>
+        }));
+
+        self.handle.spawn(task.then(move |result: Result<()>| -> ::std::result::Result<(), ()> {
+            if let Err(e) = result {
+                warn!("GCS cache maintenance pass failed: {:?}", e);
+            }
+            maintenance_running.set(false);
+            Ok(())
+        }));
+    }
+}
+
+/// Given objects already sorted oldest-first and their summed `total` size,
+/// choose the oldest objects whose deletion would bring the bucket back
+/// under `max_size`. Pure and side-effect free so the selection logic (in
+/// particular the `saturating_sub` boundary) can be unit tested without
+/// driving any futures.
+fn select_objects_to_evict(total: u64, max_size: u64, objects_oldest_first: &[GCSObjectMetadata]) -> Vec<GCSObjectMetadata> {
+    if total <= max_size {
+        return Vec::new();
+    }
+
+    let mut over = total - max_size;
+    let mut to_delete = Vec::new();
+    for object in objects_oldest_first {
+        if over == 0 {
+            break;
+        }
+        over = over.saturating_sub(object.size);
+        to_delete.push(object.clone());
+    }
+    to_delete
 }
 
 impl Storage for GCSCache {
     fn get(&self, key: &str) -> SFuture<Cache> {
+        self.maybe_spawn_maintenance_pass();
         Box::new(self.bucket.get(&key, &self.credential_provider).then(|result| {
             match result {
                 Ok(data) => {
@@ -343,6 +1097,8 @@ impl Storage for GCSCache {
             "failed to put cache entry in GCS"
         });
 
+        self.maybe_spawn_maintenance_pass();
+
         Box::new(response.map(move |_| start.elapsed()))
     }
 
@@ -350,6 +1106,101 @@ impl Storage for GCSCache {
         format!("GCS, bucket: {}", self.bucket)
     }
 
-    fn current_size(&self) -> Option<usize> { None }
-    fn max_size(&self) -> Option<usize> { None }
+    fn current_size(&self) -> Option<usize> { self.current_size.get().map(|s| s as usize) }
+    fn max_size(&self) -> Option<usize> { self.max_size.map(|s| s as usize) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_never_exceeds_max_elapsed() {
+        let config = RetryConfig {
+            max_attempts: 10,
+            base_delay: time::Duration::from_millis(500),
+            max_elapsed: time::Duration::from_secs(30),
+        };
+        for attempt in 0..20 {
+            assert!(retry_delay(&config, attempt) <= config.max_elapsed);
+        }
+    }
+
+    #[test]
+    fn retry_delay_upper_bound_grows_with_attempt_number() {
+        let config = RetryConfig {
+            max_attempts: 10,
+            base_delay: time::Duration::from_millis(10),
+            max_elapsed: time::Duration::from_secs(30),
+        };
+        // retry_delay jitters down from an exponentially growing upper bound;
+        // that upper bound should never shrink as attempts increase.
+        let upper_bound = |attempt: u32| {
+            let exp = 1u64 << attempt.min(16);
+            duration_millis(config.base_delay).saturating_mul(exp).min(duration_millis(config.max_elapsed))
+        };
+        for attempt in 0..10 {
+            assert!(upper_bound(attempt + 1) >= upper_bound(attempt));
+            assert!(retry_delay(&config, attempt) <= time::Duration::from_millis(upper_bound(attempt)));
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct SizeWrapper {
+        #[serde(deserialize_with = "deserialize_size")]
+        size: u64,
+    }
+
+    #[test]
+    fn deserialize_size_parses_gcs_string_encoded_size() {
+        let w: SizeWrapper = serde_json::from_str(r#"{"size": "1234"}"#).unwrap();
+        assert_eq!(w.size, 1234);
+    }
+
+    #[test]
+    fn deserialize_size_rejects_non_numeric_string() {
+        assert!(serde_json::from_str::<SizeWrapper>(r#"{"size": "not-a-number"}"#).is_err());
+    }
+
+    fn object(name: &str, size: u64, created_offset_secs: i64) -> GCSObjectMetadata {
+        GCSObjectMetadata {
+            name: name.to_owned(),
+            size,
+            time_created: chrono::UTC::now() + chrono::Duration::seconds(created_offset_secs),
+        }
+    }
+
+    fn names(objects: &[GCSObjectMetadata]) -> Vec<&str> {
+        objects.iter().map(|o| o.name.as_str()).collect()
+    }
+
+    #[test]
+    fn select_objects_to_evict_deletes_nothing_under_cap() {
+        let objects = vec![object("a", 10, 0), object("b", 10, 1)];
+        assert!(select_objects_to_evict(20, 100, &objects).is_empty());
+    }
+
+    #[test]
+    fn select_objects_to_evict_picks_oldest_first() {
+        let objects = vec![object("oldest", 10, 0), object("middle", 10, 1), object("newest", 10, 2)];
+        // total 30, cap 15: freeing "oldest" + "middle" (20 bytes) clears the 15-byte overage.
+        let to_delete = select_objects_to_evict(30, 15, &objects);
+        assert_eq!(names(&to_delete), vec!["oldest", "middle"]);
+    }
+
+    #[test]
+    fn select_objects_to_evict_stops_once_under_cap() {
+        let objects = vec![object("a", 10, 0), object("b", 10, 1), object("c", 10, 2)];
+        // total 30, cap 20: deleting "a" alone (10 bytes) exactly covers the overage.
+        let to_delete = select_objects_to_evict(30, 20, &objects);
+        assert_eq!(names(&to_delete), vec!["a"]);
+    }
+
+    #[test]
+    fn select_objects_to_evict_deletes_everything_if_still_insufficient() {
+        let objects = vec![object("a", 10, 0), object("b", 10, 1)];
+        // cap so small that both objects must go, even though that overshoots.
+        let to_delete = select_objects_to_evict(20, 5, &objects);
+        assert_eq!(names(&to_delete), vec!["a", "b"]);
+    }
 }